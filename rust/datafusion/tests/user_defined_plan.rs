@@ -61,7 +61,8 @@
 use futures::{FutureExt, Stream, StreamExt, TryStreamExt};
 
 use arrow::{
-    array::{Int64Array, StringArray},
+    array::new_empty_array,
+    compute::SortOptions,
     datatypes::SchemaRef,
     error::ArrowError,
     record_batch::RecordBatch,
@@ -74,18 +75,28 @@ use datafusion::{
     logical_plan::{Expr, LogicalPlan, UserDefinedLogicalNode},
     optimizer::{optimizer::OptimizerRule, utils::optimize_explain},
     physical_plan::{
-        planner::{DefaultPhysicalPlanner, ExtensionPlanner},
+        expressions::PhysicalSortExpr,
+        limit::GlobalLimitExec,
+        planner::{create_physical_sort_expr, DefaultPhysicalPlanner, ExtensionPlanner},
+        sort::SortExec,
         Distribution, ExecutionPlan, Partitioning, PhysicalPlanner, RecordBatchStream,
         SendableRecordBatchStream,
     },
     prelude::{ExecutionConfig, ExecutionContext},
+    scalar::ScalarValue,
 };
 use fmt::Debug;
 use std::task::{Context, Poll};
-use std::{any::Any, collections::BTreeMap, fmt, sync::Arc};
+use std::{
+    any::Any,
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    fmt,
+    sync::Arc,
+};
 
 use async_trait::async_trait;
-use datafusion::logical_plan::DFSchemaRef;
+use datafusion::logical_plan::{DFSchemaRef, PlanType, StringifiedPlan};
 
 /// Execute the specified sql and return the resulting record batches
 /// pretty printed as a String.
@@ -108,6 +119,41 @@ async fn setup_table(mut ctx: ExecutionContext) -> Result<ExecutionContext> {
     Ok(ctx)
 }
 
+/// Like `setup_table`, but points `sales` at a directory of several CSV
+/// files instead of one, so DataFusion gives the scan one partition per
+/// file -- exercising `TopKExec`'s two-phase local/global merge instead
+/// of the single-partition path every other test in this file takes.
+async fn setup_multi_partition_table(mut ctx: ExecutionContext) -> Result<ExecutionContext> {
+    let sql = "CREATE EXTERNAL TABLE sales(customer_id VARCHAR, revenue BIGINT) STORED AS CSV location 'tests/customer_partitioned'";
+
+    let expected = vec!["++", "++"];
+
+    let s = exec_sql(&mut ctx, sql).await?;
+    let actual = s.lines().collect::<Vec<_>>();
+
+    assert_eq!(expected, actual, "Creating multi-partition table");
+    Ok(ctx)
+}
+
+/// Like `setup_table`, but adds a nullable `region` column so a query
+/// can sort on more than one column -- used to exercise the
+/// multi-column, mixed ASC/DESC, NULLS-ordered sort key `TopKRow`
+/// supports, rather than just the single `revenue DESC` key every other
+/// test in this file sorts by.
+async fn setup_region_table(mut ctx: ExecutionContext) -> Result<ExecutionContext> {
+    let sql = "CREATE EXTERNAL TABLE region_sales(customer_id VARCHAR, region VARCHAR, revenue BIGINT) STORED AS CSV location 'tests/customer_region.csv'";
+
+    let expected = vec!["++", "++"];
+
+    let s = exec_sql(&mut ctx, sql).await?;
+    let actual = s.lines().collect::<Vec<_>>();
+
+    assert_eq!(expected, actual, "Creating region table");
+    Ok(ctx)
+}
+
+const MULTI_COLUMN_QUERY: &str = "SELECT customer_id, region, revenue FROM region_sales ORDER BY region ASC NULLS LAST, revenue DESC limit 3";
+
 const QUERY: &str =
     "SELECT customer_id, revenue FROM sales ORDER BY revenue DESC limit 3";
 
@@ -156,6 +202,48 @@ async fn topk_query() -> Result<()> {
     run_and_compare_query(ctx, "Topk context").await
 }
 
+#[tokio::test]
+// Force `sales` onto multiple partitions (one CSV file per partition)
+// and confirm the overall top 3 is still correct after TopKExec's
+// "global" phase merges every partition's local top-k -- each candidate
+// row is spread across a different partition, so getting the right
+// answer requires the merge, not just one partition's local result.
+async fn topk_multi_partition() -> Result<()> {
+    let ctx = setup_multi_partition_table(make_topk_context()).await?;
+    run_and_compare_query(ctx, "Topk context, multiple partitions").await
+}
+
+#[tokio::test]
+// Sort by `region ASC NULLS LAST, revenue DESC` to exercise
+// `TopKRow::cmp_key`/`compare_sort_values`'s lexicographic, per-column
+// direction and null-placement handling with a real multi-column key,
+// not just the single-column sort every other test here uses.
+async fn topk_multi_column_sort() -> Result<()> {
+    let mut ctx = setup_region_table(make_topk_context()).await?;
+
+    let expected = vec![
+        "+-------------+--------+---------+",
+        "| customer_id | region | revenue |",
+        "+-------------+--------+---------+",
+        "| paul        | east   | 300     |",
+        "| mia         | east   | 140     |",
+        "| andy        | west   | 150     |",
+        "+-------------+--------+---------+",
+    ];
+
+    let s = exec_sql(&mut ctx, MULTI_COLUMN_QUERY).await?;
+    let actual = s.lines().collect::<Vec<_>>();
+
+    assert_eq!(
+        expected,
+        actual,
+        "output mismatch for multi-column sort. Expected:\n{}Actual:\n{}",
+        expected.join("\n"),
+        s
+    );
+    Ok(())
+}
+
 #[tokio::test]
 // Run EXPLAIN PLAN and show the plan was in fact rewritten
 async fn topk_plan() -> Result<()> {
@@ -177,6 +265,75 @@ async fn topk_plan() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+// Run EXPLAIN (non-verbose) and confirm it only shows the final,
+// rewritten plan -- the labeled "after topk" stage is reserved for
+// EXPLAIN VERBOSE (see `topk_plan` above).
+async fn topk_plan_concise() -> Result<()> {
+    let mut ctx = setup_table(make_topk_context()).await?;
+
+    let not_expected = "logical_plan after topk";
+
+    let explain_query = format!("EXPLAIN {}", QUERY);
+    let actual_output = exec_sql(&mut ctx, &explain_query).await?;
+
+    assert!(
+        !actual_output.contains(not_expected),
+        "EXPLAIN (non-verbose) should not show the per-rule \"after topk\" stage\nActual:\n--------\n{}",
+        actual_output
+    );
+    Ok(())
+}
+
+#[tokio::test]
+// Run EXPLAIN VERBOSE and confirm the physical plan TopK produced shows
+// up as its own named stage, not just the (logical) "after topk" stage.
+async fn topk_plan_physical() -> Result<()> {
+    let mut ctx = setup_table(make_topk_context()).await?;
+
+    let expected = "physical_plan after topk-physical";
+
+    let explain_query = format!("EXPLAIN VERBOSE {}", QUERY);
+    let actual_output = exec_sql(&mut ctx, &explain_query).await?;
+
+    assert!(
+        actual_output.contains(expected),
+        "EXPLAIN VERBOSE should show TopK's physical plan as its own stage\nActual:\n--------\n{}",
+        actual_output
+    );
+    Ok(())
+}
+
+#[tokio::test]
+// `should_use_bounded_topk` decides whether `TopKPlanner::plan_extension`
+// uses the bounded heap or falls back to a plain sort + limit. Every
+// other test here runs against a CSV scan, whose statistics never
+// report a row count, so it always takes the "use the bounded heap"
+// branch -- this exercises the other branch directly, for both a
+// statistics report of zero rows and a report of a positive count that
+// is still below `k`.
+async fn topk_fallback_below_k() -> Result<()> {
+    use arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::physical_plan::empty::EmptyExec;
+
+    let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+
+    let empty: Arc<dyn ExecutionPlan> =
+        Arc::new(EmptyExec::new(false, schema.clone()));
+    assert!(
+        !should_use_bounded_topk(&empty, 3).await,
+        "an input statistics reported as empty should fall back to sort + limit, not the bounded heap"
+    );
+
+    let one_row: Arc<dyn ExecutionPlan> = Arc::new(EmptyExec::new(true, schema));
+    assert!(
+        !should_use_bounded_topk(&one_row, 3).await,
+        "an input whose statistics report 1 row, below k=3, should fall back to sort + limit, not the bounded heap"
+    );
+
+    Ok(())
+}
+
 fn make_topk_context() -> ExecutionContext {
     let config = ExecutionConfig::new().with_query_planner(Arc::new(TopKQueryPlanner {}));
 
@@ -187,6 +344,16 @@ fn make_topk_context() -> ExecutionContext {
 
 struct TopKQueryPlanner {}
 
+// NOTE: `async_trait`/`async fn` here assumes `QueryPlanner` and
+// `ExtensionPlanner` are declared with async methods upstream. That is
+// a core trait change outside this file's scope -- this extension
+// cannot make it unilaterally, and if the upstream crate pinned here
+// doesn't yet have it, this impl won't satisfy the trait and the crate
+// won't build until it does. Flagging that dependency explicitly here
+// rather than quietly planning around it, since async is what lets
+// `TopKPlanner::plan_extension` below `.await` I/O (a catalog lookup, a
+// remote statistics fetch) while choosing an operator's physical form.
+#[async_trait]
 impl QueryPlanner for TopKQueryPlanner {
     fn rewrite_logical_plan(&self, plan: LogicalPlan) -> Result<LogicalPlan> {
         TopKOptimizerRule {}.optimize(&plan)
@@ -194,7 +361,7 @@ impl QueryPlanner for TopKQueryPlanner {
 
     /// Given a `LogicalPlan` created from above, create an
     /// `ExecutionPlan` suitable for execution
-    fn create_physical_plan(
+    async fn create_physical_plan(
         &self,
         logical_plan: &LogicalPlan,
         ctx_state: &ExecutionContextState,
@@ -202,35 +369,85 @@ impl QueryPlanner for TopKQueryPlanner {
         // Teach the default physical planner how to plan TopK nodes.
         let physical_planner =
             DefaultPhysicalPlanner::with_extension_planner(Arc::new(TopKPlanner {}));
-        // Delegate most work of physical planning to the default physical planner
-        physical_planner.create_physical_plan(logical_plan, ctx_state)
+
+        if let LogicalPlan::Explain {
+            verbose: true,
+            plan,
+            stringified_plans,
+            schema,
+        } = logical_plan
+        {
+            // `OptimizerRule`/`optimize_explain` already give a custom
+            // logical-plan rewrite a named stage in `EXPLAIN VERBOSE`
+            // ("logical_plan after topk", see `TopKOptimizerRule`
+            // above). This does the same for the physical plan TopK
+            // actually produced, via the matching `OptimizedPhysicalPlan`
+            // stage type, so it isn't presented as a logical plan.
+            let physical_plan = physical_planner
+                .create_physical_plan(plan, ctx_state)
+                .await?;
+
+            let mut stringified_plans = stringified_plans.clone();
+            stringified_plans.push(StringifiedPlan::new(
+                PlanType::OptimizedPhysicalPlan {
+                    optimizer_name: "topk-physical".to_string(),
+                },
+                format!("{:?}", physical_plan),
+            ));
+
+            let explained = LogicalPlan::Explain {
+                verbose: true,
+                plan: plan.clone(),
+                stringified_plans,
+                schema: schema.clone(),
+            };
+            return physical_planner
+                .create_physical_plan(&explained, ctx_state)
+                .await;
+        }
+
+        // Delegate most work of physical planning to the default physical planner.
+        physical_planner
+            .create_physical_plan(logical_plan, ctx_state)
+            .await
     }
 }
 
+/// Example rewrite pass to insert a user defined LogicalPlanNode.
+///
+/// `optimize_explain` (below) is what gives this rule a named stage in
+/// `EXPLAIN VERBOSE`: it appends a `StringifiedPlan` labeled with
+/// `self.name()` -- "logical_plan after topk" -- each time this rule
+/// rewrites the plan, alongside the initial/final logical plan stages
+/// the core optimizer pipeline already contributes. Non-verbose
+/// `EXPLAIN` only ever shows the final plan (see `topk_plan_concise`
+/// below). `TopKQueryPlanner::create_physical_plan` contributes its own
+/// named stage the same way, by appending a "topk-physical" entry to
+/// the `Explain` node's `stringified_plans` before delegating to the
+/// default physical planner -- see `topk_plan_physical` below.
 struct TopKOptimizerRule {}
 impl OptimizerRule for TopKOptimizerRule {
-    // Example rewrite pass to insert a user defined LogicalPlanNode
     fn optimize(&mut self, plan: &LogicalPlan) -> Result<LogicalPlan> {
         match plan {
             // Note: this code simply looks for the pattern of a Limit followed by a
-            // Sort and replaces it by a TopK node. It does not handle many
-            // edge cases (e.g multiple sort columns, sort ASC / DESC), etc.
+            // Sort and replaces it by a TopK node. The full sort key (every
+            // expression, in order, each carrying its own ASC/DESC and
+            // NULLS FIRST/LAST) is captured on the TopK node so it can
+            // support arbitrary multi-column ORDER BY clauses.
             LogicalPlan::Limit { ref n, ref input } => {
                 if let LogicalPlan::Sort {
                     ref expr,
                     ref input,
                 } = **input
                 {
-                    if expr.len() == 1 {
-                        // we found a sort with a single sort expr, replace with a a TopK
-                        return Ok(LogicalPlan::Extension {
-                            node: Arc::new(TopKPlanNode {
-                                k: *n,
-                                input: self.optimize(input.as_ref())?,
-                                expr: expr[0].clone(),
-                            }),
-                        });
-                    }
+                    // we found a Limit/Sort combination, replace with a TopK
+                    return Ok(LogicalPlan::Extension {
+                        node: Arc::new(TopKPlanNode {
+                            k: *n,
+                            input: self.optimize(input.as_ref())?,
+                            expr: expr.clone(),
+                        }),
+                    });
                 }
             }
             // Due to the way explain is implemented, in order to get
@@ -264,11 +481,14 @@ impl OptimizerRule for TopKOptimizerRule {
 }
 
 struct TopKPlanNode {
+    /// The maximum number of rows to fetch -- the bound on how much
+    /// state the operator needs to keep around.
     k: usize,
     input: LogicalPlan,
-    /// The sort expression (this example only supports a single sort
-    /// expr)
-    expr: Expr,
+    /// The full sort key: one or more expressions, each an `Expr::Sort`
+    /// carrying its own ASC/DESC and NULLS FIRST/LAST. Compared
+    /// lexicographically, in order, to break ties.
+    expr: Vec<Expr>,
 }
 
 impl Debug for TopKPlanNode {
@@ -294,7 +514,7 @@ impl UserDefinedLogicalNode for TopKPlanNode {
     }
 
     fn expressions(&self) -> Vec<Expr> {
-        vec![self.expr.clone()]
+        self.expr.clone()
     }
 
     /// For example: `TopK: k=10`
@@ -308,11 +528,10 @@ impl UserDefinedLogicalNode for TopKPlanNode {
         inputs: &Vec<LogicalPlan>,
     ) -> Arc<dyn UserDefinedLogicalNode + Send + Sync> {
         assert_eq!(inputs.len(), 1, "input size inconsistent");
-        assert_eq!(exprs.len(), 1, "expression size inconsistent");
         Arc::new(TopKPlanNode {
             k: self.k,
             input: inputs[0].clone(),
-            expr: exprs[0].clone(),
+            expr: exprs.clone(),
         })
     }
 }
@@ -320,20 +539,65 @@ impl UserDefinedLogicalNode for TopKPlanNode {
 /// Physical planner for TopK nodes
 struct TopKPlanner {}
 
+// See the note on `impl QueryPlanner for TopKQueryPlanner` above: this
+// assumes `ExtensionPlanner` is declared with an async method upstream,
+// which is what lets `should_use_bounded_topk` below be `.await`ed here
+// instead of only consulting already-synchronous state.
+#[async_trait]
 impl ExtensionPlanner for TopKPlanner {
     /// Create a physical plan for an extension node
-    fn plan_extension(
+    async fn plan_extension(
         &self,
         node: &dyn UserDefinedLogicalNode,
         inputs: Vec<Arc<dyn ExecutionPlan>>,
-        _ctx_state: &ExecutionContextState,
+        ctx_state: &ExecutionContextState,
     ) -> Result<Arc<dyn ExecutionPlan>> {
         if let Some(topk_node) = node.as_any().downcast_ref::<TopKPlanNode>() {
             assert_eq!(inputs.len(), 1, "Inconsistent number of inputs");
-            // figure out input name
-            Ok(Arc::new(TopKExec {
+            let input_dfschema = topk_node.input.schema();
+            let input_schema = inputs[0].schema();
+
+            // Translate the logical sort key (arbitrary expressions, each
+            // with its own ASC/DESC and NULLS FIRST/LAST) into physical
+            // sort expressions the executor can evaluate against batches.
+            let sort = topk_node
+                .expr
+                .iter()
+                .map(|e| {
+                    create_physical_sort_expr(
+                        e,
+                        input_dfschema,
+                        input_schema.as_ref(),
+                        ctx_state,
+                    )
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            if !should_use_bounded_topk(&inputs[0], topk_node.k).await {
+                // The input's statistics report no more rows than `k`,
+                // so the bounded-heap TopK isn't worth it -- fall back
+                // to a plain sort + limit.
+                let sorted = Arc::new(SortExec::try_new(sort, inputs[0].clone())?);
+                return Ok(Arc::new(GlobalLimitExec::new(sorted, topk_node.k)));
+            }
+
+            // Two phase plan: a "local" TopK that computes the top k of
+            // each input partition, followed by a "global" TopK that
+            // collapses those per-partition results onto a single
+            // partition and merges them into the overall top k. This
+            // way TopK is correct (and still parallel) no matter how
+            // many partitions the input has.
+            let local = Arc::new(TopKExec {
                 input: inputs[0].clone(),
                 k: topk_node.k,
+                sort: sort.clone(),
+                phase: TopKPhase::Partial,
+            });
+            Ok(Arc::new(TopKExec {
+                input: local,
+                k: topk_node.k,
+                sort,
+                phase: TopKPhase::Final,
             }))
         } else {
             Err(DataFusionError::Internal(format!(
@@ -344,12 +608,59 @@ impl ExtensionPlanner for TopKPlanner {
     }
 }
 
-/// Physical operator that implements TopK for u64 data types. This
-/// code is not general and is meant as an illustration only
+/// Decides whether the bounded-heap TopK is worth planning at all, or
+/// whether a plain sort + limit would do just as well.
+///
+/// `async` so that, like the rest of this planning path, it could
+/// eventually consult I/O-backed statistics (e.g. a catalog's stored
+/// row counts) instead of only what `ExecutionPlan::statistics()`
+/// already has in memory; today it only does the latter.
+async fn should_use_bounded_topk(input: &Arc<dyn ExecutionPlan>, k: usize) -> bool {
+    match input.statistics().num_rows {
+        // Only worth the bounded heap if the input has (many) more rows
+        // than we're going to keep.
+        Some(num_rows) => num_rows > k,
+        // Unknown input size: assume it could be large/unbounded.
+        None => true,
+    }
+}
+
+/// Which half of the two-phase TopK plan an executor represents. See
+/// `TopKPlanner::plan_extension`, which (when it decides the bounded
+/// heap is worthwhile) plans a `Partial` TopKExec per input partition
+/// feeding a single `Final` TopKExec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TopKPhase {
+    /// Compute the top k rows of a single input partition
+    Partial,
+    /// Merge the already-topk'd results of every input partition (which
+    /// must therefore all be collapsed onto a single partition) into
+    /// the overall top k
+    Final,
+}
+
+impl TopKPhase {
+    fn is_final(&self) -> bool {
+        matches!(self, Self::Final)
+    }
+}
+
+/// Physical operator that implements TopK over an arbitrary multi-column
+/// sort key. It only ever keeps `k` rows of state, so its memory stays
+/// O(k) however long the input is. It emits its single result batch
+/// once the input is exhausted; an input that never ends produces no
+/// output.
 struct TopKExec {
     input: Arc<dyn ExecutionPlan>,
     /// The maxium number of values
     k: usize,
+    /// The sort key: one physical sort expression per ORDER BY column,
+    /// each carrying its own ASC/DESC and NULLS FIRST/LAST, compared in
+    /// order to break ties.
+    sort: Vec<PhysicalSortExpr>,
+    /// Whether this is the per-partition or the cross-partition merge
+    /// half of the plan
+    phase: TopKPhase,
 }
 
 impl Debug for TopKExec {
@@ -370,11 +681,20 @@ impl ExecutionPlan for TopKExec {
     }
 
     fn output_partitioning(&self) -> Partitioning {
-        Partitioning::UnknownPartitioning(1)
+        match self.phase {
+            // a local TopK does not change the number of partitions
+            TopKPhase::Partial => self.input.output_partitioning(),
+            // the global TopK merges everything onto a single partition
+            TopKPhase::Final => Partitioning::UnknownPartitioning(1),
+        }
     }
 
     fn required_child_distribution(&self) -> Distribution {
-        Distribution::UnspecifiedDistribution
+        match self.phase {
+            TopKPhase::Partial => Distribution::UnspecifiedDistribution,
+            // the merge phase must see every row, from every partition
+            TopKPhase::Final => Distribution::SinglePartition,
+        }
     }
 
     fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
@@ -389,6 +709,8 @@ impl ExecutionPlan for TopKExec {
             1 => Ok(Arc::new(TopKExec {
                 input: children[0].clone(),
                 k: self.k,
+                sort: self.sort.clone(),
+                phase: self.phase,
             })),
             _ => Err(DataFusionError::Internal(
                 "TopKExec wrong number of children".to_string(),
@@ -398,16 +720,35 @@ impl ExecutionPlan for TopKExec {
 
     /// Execute one partition and return an iterator over RecordBatch
     async fn execute(&self, partition: usize) -> Result<SendableRecordBatchStream> {
-        if 0 != partition {
-            return Err(DataFusionError::Internal(format!(
-                "TopKExec invalid partition {}",
-                partition
-            )));
+        if self.phase.is_final() {
+            if 0 != partition {
+                return Err(DataFusionError::Internal(format!(
+                    "TopKExec invalid partition {} for final phase",
+                    partition
+                )));
+            }
+
+            // pull the (already topk'd) result out of every upstream
+            // partition and merge them into the overall top k
+            let num_partitions = self.input.output_partitioning().partition_count();
+            let mut inputs = Vec::with_capacity(num_partitions);
+            for child_partition in 0..num_partitions {
+                inputs.push(self.input.execute(child_partition).await?);
+            }
+
+            return Ok(Box::pin(TopKMergeReader {
+                inputs,
+                k: self.k,
+                sort: self.sort.clone(),
+                done: false,
+                schema: self.schema(),
+            }));
         }
 
         Ok(Box::pin(TopKReader {
             input: self.input.execute(partition).await?,
             k: self.k,
+            sort: self.sort.clone(),
             done: false,
         }))
     }
@@ -419,67 +760,218 @@ struct TopKReader {
     input: SendableRecordBatchStream,
     /// Maximum number of output values
     k: usize,
+    /// The sort key to order rows by, in priority order
+    sort: Vec<PhysicalSortExpr>,
     /// Have we produced the output yet?
     done: bool,
 }
 
-/// Keeps track of the revenue from customer_id and stores if it
-/// is the top values we have seen so far.
-fn add_row(
-    top_values: &mut BTreeMap<i64, String>,
-    customer_id: &str,
-    revenue: i64,
-    k: &usize,
-) {
-    top_values.insert(revenue, customer_id.into());
-    // only keep top k
-    while top_values.len() > *k {
-        remove_lowest_value(top_values)
+/// A single row buffered inside the bounded TopK heap: the evaluated
+/// sort-key values (used to order rows) alongside the full projected
+/// row (used to reconstruct the output batch once the top k is known),
+/// plus a shared copy of the sort options (ASC/DESC, NULLS FIRST/LAST)
+/// needed to compare two keys. `Ord` has no way to thread extra
+/// context through, so every row carries its own (cheaply-cloned, via
+/// `Arc`) copy of the options it should be compared with.
+#[derive(Clone)]
+struct TopKRow {
+    sort_key: Vec<ScalarValue>,
+    row: Vec<ScalarValue>,
+    sort_options: Arc<Vec<SortOptions>>,
+}
+
+impl TopKRow {
+    /// Lexicographic comparison of `sort_key`: compare column 0, break
+    /// ties on column 1, and so on, honoring each column's direction
+    /// and null placement.
+    fn cmp_key(&self, other: &Self) -> Ordering {
+        for ((a, b), options) in self
+            .sort_key
+            .iter()
+            .zip(other.sort_key.iter())
+            .zip(self.sort_options.iter())
+        {
+            let ordering = compare_sort_values(a, b, options);
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
     }
 }
 
-fn remove_lowest_value(top_values: &mut BTreeMap<i64, String>) {
-    if !top_values.is_empty() {
-        let smallest_revenue = {
-            let (revenue, _) = top_values.iter().next().unwrap();
-            *revenue
-        };
-        top_values.remove(&smallest_revenue);
+impl PartialEq for TopKRow {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp_key(other) == Ordering::Equal
+    }
+}
+
+impl Eq for TopKRow {}
+
+// `Ord` ranks rows by how early they come in the sort order: a row that
+// sorts before another (per `cmp_key`) is the greater of the two, so the
+// bounded heap below keeps the first k rows of the ORDER BY.
+impl PartialOrd for TopKRow {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TopKRow {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cmp_key(self)
+    }
+}
+
+/// Compares two sort-key values the way `arrow::compute::sort` would,
+/// honoring `options`'s direction and null placement.
+fn compare_sort_values(a: &ScalarValue, b: &ScalarValue, options: &SortOptions) -> Ordering {
+    let ordering = match (a.is_null(), b.is_null()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => {
+            return if options.nulls_first {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            }
+        }
+        (false, true) => {
+            return if options.nulls_first {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            }
+        }
+        // `ScalarValue::partial_cmp` only returns `None` for a NaN
+        // float value -- perfectly reachable from a plain
+        // `ORDER BY float_col LIMIT k` over valid data, not a sign of
+        // corrupt input. Treat NaN as greater than every other value,
+        // the same way `arrow::compute::sort` orders it, instead of
+        // panicking.
+        (false, false) => a.partial_cmp(b).unwrap_or_else(|| {
+            match (scalar_is_nan(a), scalar_is_nan(b)) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => Ordering::Equal,
+            }
+        }),
+    };
+
+    if options.descending {
+        ordering.reverse()
+    } else {
+        ordering
+    }
+}
+
+/// Whether `value` is a floating point NaN. Used by `compare_sort_values`
+/// to order NaNs consistently instead of relying on `partial_cmp`, which
+/// returns `None` for them.
+fn scalar_is_nan(value: &ScalarValue) -> bool {
+    match value {
+        ScalarValue::Float32(Some(v)) => v.is_nan(),
+        ScalarValue::Float64(Some(v)) => v.is_nan(),
+        _ => false,
+    }
+}
+
+/// Keeps track of the rows seen so far, retaining only the `k` rows
+/// that come first in the sort order given by each row's `sort_key`.
+/// `top_values` is a min-heap bounded at size `k` (via `Reverse` so the
+/// smallest entry -- per `TopKRow`'s `Ord`, the row that comes last in
+/// the sort order -- is the one `peek`/`pop` expose),
+/// so a row is only ever inserted if it beats the current smallest
+/// entry -- losers are rejected in O(log k) without ever growing the
+/// heap past `k` entries. This keeps memory at O(k) regardless of how
+/// many rows arrive.
+fn add_row(top_values: &mut BinaryHeap<Reverse<TopKRow>>, row: TopKRow, k: &usize) {
+    if top_values.len() < *k {
+        top_values.push(Reverse(row));
+        return;
+    }
+
+    // heap is full: only replace the current minimum if this row beats it
+    if let Some(Reverse(min_row)) = top_values.peek() {
+        if row.cmp(min_row) == Ordering::Greater {
+            top_values.pop();
+            top_values.push(Reverse(row));
+        }
     }
 }
 
 fn accumulate_batch(
     input_batch: &RecordBatch,
-    mut top_values: BTreeMap<i64, String>,
+    sort: &[PhysicalSortExpr],
+    mut top_values: BinaryHeap<Reverse<TopKRow>>,
     k: &usize,
-) -> Result<BTreeMap<i64, String>> {
+) -> Result<BinaryHeap<Reverse<TopKRow>>> {
     let num_rows = input_batch.num_rows();
-    // Assuming the input columns are
-    // column[0]: customer_id / UTF8
-    // column[1]: revenue: Int64
-    let customer_id = input_batch
-        .column(0)
-        .as_any()
-        .downcast_ref::<StringArray>()
-        .expect("Column 0 is not customer_id");
-
-    let revenue = input_batch
-        .column(1)
-        .as_any()
-        .downcast_ref::<Int64Array>()
-        .expect("Column 1 is not revenue");
-
-    for row in 0..num_rows {
+
+    // evaluate each sort expression once per batch to get the sort-key
+    // columns, rather than re-evaluating per row
+    let sort_key_columns = sort
+        .iter()
+        .map(|e| e.expr.evaluate(input_batch).map(|v| v.into_array(num_rows)))
+        .collect::<Result<Vec<_>>>()?;
+    let sort_options: Arc<Vec<SortOptions>> =
+        Arc::new(sort.iter().map(|e| e.options).collect());
+
+    for row_idx in 0..num_rows {
+        let sort_key = sort_key_columns
+            .iter()
+            .map(|array| ScalarValue::try_from_array(array, row_idx))
+            .collect::<Result<Vec<_>>>()?;
+
+        let row = (0..input_batch.num_columns())
+            .map(|col| ScalarValue::try_from_array(input_batch.column(col), row_idx))
+            .collect::<Result<Vec<_>>>()?;
+
         add_row(
             &mut top_values,
-            customer_id.value(row),
-            revenue.value(row),
+            TopKRow {
+                sort_key,
+                row,
+                sort_options: sort_options.clone(),
+            },
             k,
         );
     }
     Ok(top_values)
 }
 
+/// Drains `top_values` (ascending, since it is a min-heap), reverses it
+/// into descending order -- which is the sort order -- and reassembles the buffered rows into a
+/// single `RecordBatch` matching `schema`.
+fn build_output_batch(
+    schema: SchemaRef,
+    top_values: BinaryHeap<Reverse<TopKRow>>,
+) -> Result<RecordBatch> {
+    let mut top_values = top_values;
+    let mut rows = Vec::with_capacity(top_values.len());
+    while let Some(Reverse(row)) = top_values.pop() {
+        rows.push(row);
+    }
+    rows.reverse();
+
+    // `ScalarValue::iter_to_array` infers each column's Arrow type from
+    // the scalar values present, so it has nothing to infer from when no
+    // rows survived (an empty table, a fully-filtered input, `LIMIT 0`).
+    // Build directly from the schema's declared type in that case so an
+    // empty result is still a legitimate empty batch, not an error.
+    let columns = (0..schema.fields().len())
+        .map(|col| {
+            if rows.is_empty() {
+                Ok(new_empty_array(schema.field(col).data_type()))
+            } else {
+                ScalarValue::iter_to_array(rows.iter().map(|r| r.row[col].clone()))
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    RecordBatch::try_new(schema, columns).map_err(DataFusionError::ArrowError)
+}
+
 impl Stream for TopKReader {
     type Item = std::result::Result<RecordBatch, ArrowError>;
 
@@ -495,35 +987,23 @@ impl Stream for TopKReader {
 
         // take this as immutable
         let k = self.k;
+        let sort = self.sort.clone();
         let schema = self.schema();
 
-        let top_values = self
-            .input
-            .as_mut()
-            // Hard coded implementation for sales / customer_id example as BTree
-            .try_fold(
-                BTreeMap::<i64, String>::new(),
-                move |top_values, batch| async move {
-                    accumulate_batch(&batch, top_values, &k)
+        let top_values = self.input.as_mut().try_fold(
+            BinaryHeap::<Reverse<TopKRow>>::new(),
+            move |top_values, batch| {
+                let sort = sort.clone();
+                async move {
+                    accumulate_batch(&batch, &sort, top_values, &k)
                         .map_err(DataFusionError::into_arrow_external_error)
-                },
-            );
-
-        let top_values = top_values.map(|top_values| match top_values {
-            Ok(top_values) => {
-                // make output by walking over the map backwards (so values are descending)
-                let (revenue, customer): (Vec<i64>, Vec<&String>) =
-                    top_values.iter().rev().unzip();
-
-                let customer: Vec<&str> = customer.iter().map(|&s| &**s).collect();
-                Ok(RecordBatch::try_new(
-                    schema,
-                    vec![
-                        Arc::new(StringArray::from(customer)),
-                        Arc::new(Int64Array::from(revenue)),
-                    ],
-                )?)
-            }
+                }
+            },
+        );
+
+        let top_values = top_values.map(move |top_values| match top_values {
+            Ok(top_values) => build_output_batch(schema, top_values)
+                .map_err(DataFusionError::into_arrow_external_error),
             Err(e) => Err(e),
         });
         let mut top_values = Box::pin(top_values.into_stream());
@@ -537,3 +1017,65 @@ impl RecordBatchStream for TopKReader {
         self.input.schema()
     }
 }
+
+/// The "global" half of the two-phase TopK: reads the single,
+/// already-topk'd batch produced by each upstream partition's
+/// `TopKReader` and merges them with the same bounded-heap logic to
+/// produce the overall top k.
+struct TopKMergeReader {
+    /// One input stream per upstream partition
+    inputs: Vec<SendableRecordBatchStream>,
+    /// Maximum number of output values
+    k: usize,
+    /// The sort key to order rows by, in priority order
+    sort: Vec<PhysicalSortExpr>,
+    /// Have we produced the output yet?
+    done: bool,
+    schema: SchemaRef,
+}
+
+impl Stream for TopKMergeReader {
+    type Item = std::result::Result<RecordBatch, ArrowError>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        self.done = true;
+
+        let k = self.k;
+        let sort = self.sort.clone();
+        let schema = self.schema.clone();
+        let inputs = std::mem::take(&mut self.inputs);
+
+        let merged = async move {
+            let mut top_values = BinaryHeap::<Reverse<TopKRow>>::new();
+            for mut input in inputs {
+                let batches: Vec<RecordBatch> = input.as_mut().try_collect().await?;
+                for batch in &batches {
+                    top_values = accumulate_batch(batch, &sort, top_values, &k)
+                        .map_err(DataFusionError::into_arrow_external_error)?;
+                }
+            }
+            Ok(top_values)
+        };
+
+        let merged = merged.map(move |top_values| match top_values {
+            Ok(top_values) => build_output_batch(schema, top_values)
+                .map_err(DataFusionError::into_arrow_external_error),
+            Err(e) => Err(e),
+        });
+        let mut merged = Box::pin(merged.into_stream());
+
+        merged.poll_next_unpin(cx)
+    }
+}
+
+impl RecordBatchStream for TopKMergeReader {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}